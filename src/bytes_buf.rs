@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use bytes::{Bytes, BytesMut};
+
+/// An append-on-the-right, take-from-the-left buffer made of `Bytes`
+/// chunks, so that slicing a frame off the front never copies the chunk
+/// bodies it didn't have to.
+///
+/// Appending a chunk is always O(1). Taking `n` bytes off the front is also
+/// O(1) (a refcounted slice of the front chunk) as long as `n` doesn't
+/// cross a chunk boundary; only that rarer case copies.
+#[derive(Clone, Debug, Default)]
+pub struct BytesBuf {
+    /// Bytes already folded into one contiguous run by a previous
+    /// `as_contiguous` call. Kept as `BytesMut` so the next `extend`s can
+    /// grow it in place instead of re-copying it from scratch.
+    merged: BytesMut,
+    /// Chunks appended since the last `as_contiguous` call, not yet folded
+    /// into `merged`.
+    chunks: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl BytesBuf {
+    pub fn new() -> Self {
+        BytesBuf {
+            merged: BytesMut::new(),
+            chunks: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    /// The number of bytes currently buffered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends `bytes` to the right of the buffer without copying it.
+    pub fn extend(&mut self, bytes: Bytes) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        self.len += bytes.len();
+        self.chunks.push_back(bytes);
+    }
+
+    /// Returns a contiguous view of everything currently buffered. Folds
+    /// any chunks appended since the last call into `merged`, growing it in
+    /// place so bytes merged last time aren't copied again just because one
+    /// more chunk arrived — a payload spanning N reads costs O(N) total,
+    /// not O(N^2).
+    pub fn as_contiguous(&mut self) -> &[u8] {
+        for chunk in self.chunks.drain(..) {
+            self.merged.extend_from_slice(&chunk);
+        }
+
+        &self.merged
+    }
+
+    /// Removes and returns the first `n` bytes. Panics if fewer than `n`
+    /// bytes are currently buffered.
+    pub fn take(&mut self, n: usize) -> Bytes {
+        assert!(n <= self.len, "not enough buffered bytes to take {}", n);
+
+        self.len -= n;
+
+        if n <= self.merged.len() {
+            return self.merged.split_to(n).freeze();
+        }
+
+        // With nothing merged yet (the common case for a caller that never
+        // calls `as_contiguous`, e.g. `Simple`'s chunk-streaming states),
+        // `n` fitting entirely within the front chunk is still a O(1)
+        // refcounted slice, not a copy.
+        if self.merged.is_empty() {
+            if let Some(front) = self.chunks.front_mut() {
+                if n <= front.len() {
+                    let taken = front.split_to(n);
+
+                    if front.is_empty() {
+                        self.chunks.pop_front();
+                    }
+
+                    return taken;
+                }
+            }
+        }
+
+        // `n` spans the already-merged bytes and at least one chunk that
+        // hasn't been folded in yet, or crosses a boundary between two
+        // unmerged chunks: the only cases that still have to copy.
+        let mut taken = Vec::with_capacity(n);
+        taken.extend_from_slice(&self.merged.split_to(self.merged.len()));
+        let mut remaining = n - taken.len();
+
+        while remaining > 0 {
+            let mut chunk = self.chunks.pop_front().expect("not enough buffered bytes");
+
+            if chunk.len() <= remaining {
+                remaining -= chunk.len();
+                taken.extend_from_slice(&chunk);
+            } else {
+                taken.extend_from_slice(&chunk.split_to(remaining));
+                self.chunks.push_front(chunk);
+                remaining = 0;
+            }
+        }
+
+        Bytes::from(taken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn as_contiguous_merges_chunks_appended_since_the_last_call() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(b"abc"));
+        buf.extend(Bytes::from_static(b"def"));
+
+        assert_eq!(buf.as_contiguous(), b"abcdef");
+
+        // A chunk appended after a merge shouldn't disturb what was
+        // already folded in; it should just extend it.
+        buf.extend(Bytes::from_static(b"ghi"));
+        assert_eq!(buf.as_contiguous(), b"abcdefghi");
+    }
+
+    #[test]
+    fn take_within_the_front_chunk_without_merging_first() {
+        let mut buf = BytesBuf::new();
+        // Not `from_static`: a heap-backed `Bytes` so `as_ptr` actually
+        // identifies its one shared allocation rather than `'static` data
+        // every clone would also point at.
+        let first = Bytes::from(b"abcdef".to_vec());
+        let first_ptr = first.as_ptr();
+        buf.extend(first);
+        buf.extend(Bytes::from(b"ghi".to_vec()));
+
+        // `take` within a single unmerged chunk must return a slice of that
+        // same `Bytes` (sharing its allocation), not a freshly copied one -
+        // this is the hot path `Simple`'s chunk-streaming states rely on,
+        // and they never call `as_contiguous` first.
+        let taken = buf.take(4);
+        assert_eq!(taken, Bytes::from_static(b"abcd"));
+        assert_eq!(taken.as_ptr(), first_ptr);
+
+        assert_eq!(buf.take(5), Bytes::from_static(b"efghi"));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn take_within_and_across_the_merged_boundary() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(b"abc"));
+        buf.extend(Bytes::from_static(b"def"));
+        buf.as_contiguous();
+
+        assert_eq!(buf.take(2), Bytes::from_static(b"ab"));
+
+        // Appending more before the next `as_contiguous` call leaves it
+        // unmerged; `take` spanning both pieces must still work.
+        buf.extend(Bytes::from_static(b"ghi"));
+        assert_eq!(buf.take(5), Bytes::from_static(b"cdefg"));
+        assert_eq!(buf.take(2), Bytes::from_static(b"hi"));
+        assert!(buf.is_empty());
+    }
+}
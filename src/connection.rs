@@ -1,32 +1,48 @@
 use std::io::Cursor;
 
+use futures::stream::{self, Stream};
 use protocol::wire::middleware::pipeline::{self, Pipeline};
 use protocol::{Error, Parcel, Settings};
 use tokio::io::{split, AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 
-use super::transport::{Simple, Transport};
+use super::inmemory::InmemoryStream;
+use super::transport::{Codec, FixedSizeCodec, RawChunk, Simple, Transport, MAX_CHUNK_SIZE};
 
-async fn receive_packet<P: Parcel, S: AsyncRead + Send + Unpin>(
-    transport: &mut Simple,
+fn io_error(message: impl Into<String>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message.into()).into()
+}
+
+async fn receive_packet<P: Parcel, S: AsyncRead + Send + Unpin, C: Codec>(
+    transport: &mut Simple<C>,
     stream: &mut S,
     settings: &Settings,
     middleware: &mut pipeline::Default,
 ) -> Result<Option<P>, Error> {
-    transport.process_data(stream, &settings).await?;
+    // As in `receive_stream`, `eof` remembers whether the previous pass
+    // already hit end-of-stream with nothing new to show for it, so a peer
+    // disconnecting doesn't just make this busy-spin re-reading a closed
+    // socket forever.
+    let mut eof = false;
+
+    loop {
+        if let Some(raw_packet) = transport.receive_raw_packet().await? {
+            let mut packet_data = Cursor::new(middleware.decode_data(raw_packet.to_vec())?);
 
-    if let Some(raw_packet) = transport.receive_raw_packet().await? {
-        let mut packet_data = Cursor::new(middleware.decode_data(raw_packet)?);
+            let packet = P::read(&mut packet_data, settings)?;
 
-        let packet = P::read(&mut packet_data, settings)?;
+            return Ok(Some(packet));
+        }
+
+        if eof {
+            return Err(io_error("peer closed the connection before sending a packet"));
+        }
 
-        Ok(Some(packet))
-    } else {
-        Ok(None)
+        eof = transport.process_data(stream, settings).await?;
     }
 }
 
-async fn send_packet<P: Parcel, S: AsyncWrite + Send + Unpin>(
-    transport: &mut Simple,
+async fn send_packet<P: Parcel, S: AsyncWrite + Send + Unpin, C: Codec>(
+    transport: &mut Simple<C>,
     stream: &mut S,
     settings: &Settings,
     middleware: &mut pipeline::Default,
@@ -38,27 +54,112 @@ async fn send_packet<P: Parcel, S: AsyncWrite + Send + Unpin>(
         .await
 }
 
+/// Sends `body` as a sequence of chunked frames, so that a caller never needs
+/// to hold the whole payload in memory on the wire side.
+async fn send_stream<S: AsyncWrite + Send + Unpin, C: Codec>(
+    transport: &mut Simple<C>,
+    stream: &mut S,
+    settings: &Settings,
+    body: &[u8],
+) -> Result<(), Error> {
+    let mut remaining = body;
+
+    loop {
+        let chunk_len = remaining.len().min(MAX_CHUNK_SIZE);
+        let (chunk, rest) = remaining.split_at(chunk_len);
+        let more_follows = !rest.is_empty();
+
+        transport
+            .send_raw_chunk(stream, chunk, more_follows, settings)
+            .await?;
+
+        if !more_follows {
+            return Ok(());
+        }
+
+        remaining = rest;
+    }
+}
+
+/// Receives a streamed body as a sequence of chunks, yielding each one as
+/// soon as it arrives instead of buffering the whole payload.
+fn receive_stream<'a, S: AsyncRead + Send + Unpin, C: Codec>(
+    transport: &'a mut Simple<C>,
+    stream: &'a mut S,
+    settings: &'a Settings,
+) -> impl Stream<Item = Result<Vec<u8>, Error>> + 'a {
+    transport.begin_receiving_stream();
+
+    // `eof` remembers whether the last `process_data` call already hit
+    // end-of-stream with nothing new to show for it, so that a peer
+    // disconnecting mid-frame ends this stream with an error instead of
+    // spinning forever re-reading a closed socket.
+    stream::unfold(
+        (transport, stream, false),
+        move |(transport, stream, mut eof)| async move {
+            loop {
+                match transport.receive_raw_chunk().await {
+                    Ok(Some(RawChunk::Data(data))) => {
+                        return Some((Ok(data.to_vec()), (transport, stream, eof)))
+                    }
+                    Ok(Some(RawChunk::End)) => return None,
+                    Ok(None) if eof => {
+                        return Some((
+                            Err(io_error("peer closed the connection mid-stream")),
+                            (transport, stream, eof),
+                        ));
+                    }
+                    Ok(None) => {}
+                    Err(err) => return Some((Err(err), (transport, stream, eof))),
+                }
+
+                match transport.process_data(stream, settings).await {
+                    Ok(reached_eof) => eof = reached_eof,
+                    Err(err) => return Some((Err(err), (transport, stream, eof))),
+                }
+            }
+        },
+    )
+}
+
 /// A stream-based connection.
 #[derive(Debug)]
-pub struct Connection<P: Parcel, S: AsyncRead + AsyncWrite + Send + Unpin> {
+pub struct Connection<
+    P: Parcel,
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    C: Codec = FixedSizeCodec,
+> {
     pub stream: S,
-    pub transport: Simple,
+    pub transport: Simple<C>,
     pub middleware: pipeline::Default,
     pub settings: Settings,
 
     pub _parcel: std::marker::PhantomData<P>,
 }
 
-impl<P, S> Connection<P, S>
+impl<P, S, C> Connection<P, S, C>
 where
     P: Parcel,
     S: AsyncRead + AsyncWrite + Send + Unpin,
+    C: Codec + Default,
 {
     /// Creates a new connection.
     pub fn new(stream: S, settings: Settings) -> Self {
+        Self::with_codec(stream, settings, C::default())
+    }
+}
+
+impl<P, S, C> Connection<P, S, C>
+where
+    P: Parcel,
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    C: Codec,
+{
+    /// Creates a new connection using `codec` to frame packets.
+    pub fn with_codec(stream: S, settings: Settings, codec: C) -> Self {
         Self {
             stream,
-            transport: Simple::new(),
+            transport: Simple::with_codec(codec),
             middleware: pipeline::default(),
             settings,
             _parcel: std::marker::PhantomData,
@@ -88,48 +189,125 @@ where
         .await
     }
 
+    /// Sends `body` as a sequence of chunked frames rather than a single
+    /// packet, so that its size is not bounded by available memory.
+    pub async fn send_stream(&mut self, body: &[u8]) -> Result<(), Error> {
+        send_stream(&mut self.transport, &mut self.stream, &self.settings, body).await
+    }
+
+    /// Receives a streamed body as a sequence of chunks, without ever
+    /// buffering the whole payload in memory.
+    pub fn receive_stream(&mut self) -> impl Stream<Item = Result<Vec<u8>, Error>> + '_ {
+        receive_stream(&mut self.transport, &mut self.stream, &self.settings)
+    }
+
     pub fn into_inner(self) -> S {
         self.stream
     }
 
-    pub fn split(self) -> (ReceiveConnection<P, S>, SendConnection<P, S>) {
+    /// Caps how many buffered-but-undelivered bytes `receive_packet`/
+    /// `receive_stream` will accumulate before they stop reading from the
+    /// stream, applying backpressure to the peer until the buffered data
+    /// is consumed. See [`Simple::with_backpressure_limit`].
+    pub fn with_backpressure_limit(mut self, limit: usize) -> Self {
+        self.transport = self.transport.with_backpressure_limit(limit);
+        self
+    }
+
+    /// Splits this connection into independent read/write halves, carrying
+    /// over `self`'s actual `transport` (its codec, and anything it had
+    /// already buffered) rather than starting each half over with a fresh,
+    /// default one.
+    pub fn split(self) -> (ReceiveConnection<P, S, C>, SendConnection<P, S, C>) {
         let settings = self.settings.clone();
-        let (receiver, sender) = split(self.into_inner());
+        let transport = self.transport;
+        let (receiver, sender) = split(self.stream);
 
         (
-            ReceiveConnection::new(receiver, settings.clone()),
-            SendConnection::new(sender, settings),
+            ReceiveConnection::with_transport(receiver, settings.clone(), transport.clone()),
+            SendConnection::with_transport(sender, settings, transport),
         )
     }
 }
 
+impl<P, C> Connection<P, InmemoryStream, C>
+where
+    P: Parcel,
+    C: Codec + Default,
+{
+    /// Creates two connections wired directly to each other through an
+    /// in-memory duplex stream, so a protocol can be exercised end-to-end
+    /// in tests without real sockets. Each side buffers up to `capacity`
+    /// bytes before a `send_packet`/`send_stream` call on it blocks until
+    /// the other side reads some of it.
+    pub fn pair(settings: Settings, capacity: usize) -> (Self, Self) {
+        let (a, b) = InmemoryStream::pair(capacity);
+        (Self::new(a, settings.clone()), Self::new(b, settings))
+    }
+
+    /// Like [`pair`](Self::pair), but returns each side already split into
+    /// its [`SendConnection`]/[`ReceiveConnection`] halves.
+    #[allow(clippy::type_complexity)]
+    pub fn pair_split(
+        settings: Settings,
+        capacity: usize,
+    ) -> (
+        (ReceiveConnection<P, InmemoryStream, C>, SendConnection<P, InmemoryStream, C>),
+        (ReceiveConnection<P, InmemoryStream, C>, SendConnection<P, InmemoryStream, C>),
+    ) {
+        let (a, b) = Self::pair(settings, capacity);
+        (a.split(), b.split())
+    }
+}
+
 /// A stream-based connection.
 #[derive(Debug)]
-pub struct SendConnection<P: Parcel, S: AsyncWrite + Send + Unpin> {
+pub struct SendConnection<P: Parcel, S: AsyncWrite + Send + Unpin, C: Codec = FixedSizeCodec> {
     pub writer: WriteHalf<S>,
-    pub transport: Simple,
+    pub transport: Simple<C>,
     pub middleware: pipeline::Default,
     pub settings: Settings,
 
     pub _parcel: std::marker::PhantomData<P>,
 }
 
-impl<P, S> SendConnection<P, S>
+impl<P, S, C> SendConnection<P, S, C>
 where
     P: Parcel,
     S: AsyncWrite + Send + Unpin,
+    C: Codec + Default,
 {
     /// Creates a new connection.
     pub fn new(writer: WriteHalf<S>, settings: Settings) -> Self {
+        Self::with_transport(writer, settings, Simple::new())
+    }
+}
+
+impl<P, S, C> SendConnection<P, S, C>
+where
+    P: Parcel,
+    S: AsyncWrite + Send + Unpin,
+    C: Codec,
+{
+    /// Creates a new connection reusing an already-constructed `transport`
+    /// (and therefore its codec), e.g. when splitting a [`Connection`].
+    pub fn with_transport(writer: WriteHalf<S>, settings: Settings, transport: Simple<C>) -> Self {
         Self {
             writer,
-            transport: Simple::new(),
+            transport,
             middleware: pipeline::default(),
             settings,
             _parcel: std::marker::PhantomData,
         }
     }
+}
 
+impl<P, S, C> SendConnection<P, S, C>
+where
+    P: Parcel,
+    S: AsyncWrite + Send + Unpin,
+    C: Codec,
+{
     /// Sends a packet.
     pub async fn send_packet(&mut self, packet: &P) -> Result<(), Error> {
         send_packet(
@@ -142,6 +320,12 @@ where
         .await
     }
 
+    /// Sends `body` as a sequence of chunked frames rather than a single
+    /// packet, so that its size is not bounded by available memory.
+    pub async fn send_stream(&mut self, body: &[u8]) -> Result<(), Error> {
+        send_stream(&mut self.transport, &mut self.writer, &self.settings, body).await
+    }
+
     pub fn into_inner(self) -> WriteHalf<S> {
         self.writer
     }
@@ -149,25 +333,40 @@ where
 
 /// A stream-based connection.
 #[derive(Debug)]
-pub struct ReceiveConnection<P: Parcel, S: AsyncRead + Send + Unpin> {
+pub struct ReceiveConnection<P: Parcel, S: AsyncRead + Send + Unpin, C: Codec = FixedSizeCodec> {
     pub reader: ReadHalf<S>,
-    pub transport: Simple,
+    pub transport: Simple<C>,
     pub middleware: pipeline::Default,
     pub settings: Settings,
 
     pub _parcel: std::marker::PhantomData<P>,
 }
 
-impl<P, S> ReceiveConnection<P, S>
+impl<P, S, C> ReceiveConnection<P, S, C>
 where
     P: Parcel,
     S: AsyncRead + Send + Unpin,
+    C: Codec + Default,
 {
     /// Creates a new connection.
     pub fn new(reader: ReadHalf<S>, settings: Settings) -> Self {
+        Self::with_transport(reader, settings, Simple::new())
+    }
+}
+
+impl<P, S, C> ReceiveConnection<P, S, C>
+where
+    P: Parcel,
+    S: AsyncRead + Send + Unpin,
+    C: Codec,
+{
+    /// Creates a new connection reusing an already-constructed `transport`
+    /// (and therefore its codec and anything it had already buffered),
+    /// e.g. when splitting a [`Connection`].
+    pub fn with_transport(reader: ReadHalf<S>, settings: Settings, transport: Simple<C>) -> Self {
         Self {
             reader,
-            transport: Simple::new(),
+            transport,
             middleware: pipeline::default(),
             settings,
             _parcel: std::marker::PhantomData,
@@ -185,7 +384,120 @@ where
         .await
     }
 
+    /// Receives a streamed body as a sequence of chunks, without ever
+    /// buffering the whole payload in memory.
+    pub fn receive_stream(&mut self) -> impl Stream<Item = Result<Vec<u8>, Error>> + '_ {
+        receive_stream(&mut self.transport, &mut self.reader, &self.settings)
+    }
+
     pub fn into_inner(self) -> ReadHalf<S> {
         self.reader
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use protocol::Settings;
+
+    use super::super::inmemory::DEFAULT_CAPACITY;
+    use super::super::transport::DelimiterCodec;
+    use super::*;
+
+    #[tokio::test]
+    async fn send_stream_round_trips_through_receive_stream() {
+        let (mut a, mut b) =
+            Connection::<u32, InmemoryStream>::pair(Settings::default(), DEFAULT_CAPACITY);
+
+        let body = b"a streamed payload split across several chunks".to_vec();
+        let sender = tokio::spawn(async move { a.send_stream(&body).await.unwrap() });
+
+        let mut received = Vec::new();
+        {
+            let mut chunks = b.receive_stream();
+            while let Some(chunk) = chunks.next().await {
+                received.extend(chunk.unwrap());
+            }
+        }
+
+        sender.await.unwrap();
+        assert_eq!(received, b"a streamed payload split across several chunks");
+    }
+
+    #[tokio::test]
+    async fn receive_stream_errors_instead_of_spinning_when_peer_disconnects_mid_stream() {
+        let (mut a, mut b) =
+            Connection::<u32, InmemoryStream>::pair(Settings::default(), DEFAULT_CAPACITY);
+
+        // Send one chunk but never the one that completes the stream, then
+        // disappear: `b` should report the disconnect rather than busy-loop
+        // re-reading a closed socket forever.
+        a.transport
+            .send_raw_chunk(&mut a.stream, b"partial", true, &a.settings)
+            .await
+            .unwrap();
+        drop(a);
+
+        let (first, second) = tokio::time::timeout(Duration::from_secs(1), async {
+            let mut chunks = b.receive_stream();
+            let first = chunks.next().await;
+            let second = chunks.next().await;
+            (first, second)
+        })
+        .await
+        .expect("receive_stream should report the disconnect instead of hanging");
+
+        // The chunk that did arrive is still yielded first; the disconnect
+        // is reported on the next poll.
+        assert_eq!(first.unwrap().unwrap(), b"partial");
+        assert!(second.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn split_preserves_the_original_codec() {
+        let settings = Settings::default();
+        let (stream_a, stream_b) = InmemoryStream::pair(DEFAULT_CAPACITY);
+
+        let connection = Connection::<u32, InmemoryStream, DelimiterCodec>::with_codec(
+            stream_a,
+            settings.clone(),
+            DelimiterCodec::new(0),
+        );
+        let peer = Connection::<u32, InmemoryStream, DelimiterCodec>::with_codec(
+            stream_b,
+            settings,
+            DelimiterCodec::new(0),
+        );
+
+        let (mut receiver, _receiver_sender) = connection.split();
+        let (_peer_receiver, mut sender) = peer.split();
+
+        // The payload embeds the *default* codec's delimiter (`\n`) but not
+        // the custom one actually configured (`\0`). Before the fix, split
+        // rebuilt each half with `C::default()`, so this would get framed
+        // and decoded at the wrong byte and come back truncated.
+        let payload = b"abc\ndef";
+        sender
+            .transport
+            .send_raw_packet(&mut sender.writer, payload, &sender.settings)
+            .await
+            .unwrap();
+
+        let received = loop {
+            let eof = receiver
+                .transport
+                .process_data(&mut receiver.reader, &receiver.settings)
+                .await
+                .unwrap();
+            assert!(!eof, "peer closed before the frame arrived");
+
+            if let Some(raw) = receiver.transport.receive_raw_packet().await.unwrap() {
+                break raw;
+            }
+        };
+
+        assert_eq!(&received[..], payload);
+    }
+}
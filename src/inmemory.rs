@@ -0,0 +1,51 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+/// The buffer capacity [`InmemoryStream::pair`] uses when a caller doesn't
+/// need a specific one.
+pub const DEFAULT_CAPACITY: usize = 4 * 1024;
+
+/// An in-memory byte stream pairable with another instance of itself, so a
+/// protocol can be exercised end-to-end without real sockets.
+#[derive(Debug)]
+pub struct InmemoryStream(DuplexStream);
+
+impl InmemoryStream {
+    /// Creates two endpoints whose writes feed each other's reads. Each
+    /// side buffers up to `capacity` bytes before a write blocks until the
+    /// other side reads some of it.
+    pub fn pair(capacity: usize) -> (InmemoryStream, InmemoryStream) {
+        let (a, b) = tokio::io::duplex(capacity);
+        (InmemoryStream(a), InmemoryStream(b))
+    }
+}
+
+impl AsyncRead for InmemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for InmemoryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -1,9 +1,27 @@
+mod bytes_buf;
 mod connection;
+mod inmemory;
+mod multiplex;
+mod secure;
 mod transport;
 
 pub use crate::connection::{
     Connection as AsyncConnection, ReceiveConnection as AsyncReceiveConnection,
     SendConnection as AsyncSendConnection,
 };
+pub use crate::inmemory::InmemoryStream as AsyncInmemoryStream;
+pub use crate::inmemory::DEFAULT_CAPACITY as ASYNC_INMEMORY_DEFAULT_CAPACITY;
+pub use crate::multiplex::{
+    MultiplexedConnection as AsyncMultiplexedConnection, Priority as AsyncStreamPriority,
+    Role as AsyncStreamRole, StreamId as AsyncStreamId, StreamReceiver as AsyncStreamReceiver,
+    StreamSender as AsyncStreamSender, DEFAULT_PRIORITY as ASYNC_DEFAULT_STREAM_PRIORITY,
+};
+pub use crate::secure::Keypair as AsyncKeypair;
+pub use crate::secure::SecureConnection as AsyncSecureConnection;
+pub use crate::transport::Codec as AsyncCodec;
+pub use crate::transport::DelimiterCodec as AsyncDelimiterCodec;
+pub use crate::transport::FixedSizeCodec as AsyncFixedSizeCodec;
+pub use crate::transport::RawChunk as AsyncRawChunk;
 pub use crate::transport::Simple as AsyncSimple;
 pub use crate::transport::Transport as AsyncTransport;
+pub use crate::transport::VarintCodec as AsyncVarintCodec;
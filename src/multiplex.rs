@@ -0,0 +1,681 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Cursor;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use protocol::{Error, Parcel, Settings};
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+use super::transport::{
+    read_chunk, ChunkSize, FixedSizeCodec, Simple, Transport, CHUNK_LENGTH_MASK, MAX_CHUNK_SIZE,
+    MORE_FOLLOWS_FLAG,
+};
+
+fn io_error(message: impl Into<String>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message.into()).into()
+}
+
+/// The type used to identify a logical stream multiplexed over one
+/// connection.
+pub type StreamId = u32;
+
+/// The priority a stream is scheduled with when there is more than one
+/// chunk ready to send; higher values are served first.
+pub type Priority = u8;
+
+/// The lowest priority, for a stream opened or accepted with no particular
+/// urgency.
+pub const DEFAULT_PRIORITY: Priority = 0;
+
+/// Which side of the connection allocated a stream, which decides the
+/// parity of the ids it hands out so the two sides never collide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Allocates odd stream ids.
+    Initiator,
+    /// Allocates even stream ids.
+    Responder,
+}
+
+/// Demultiplexes incoming chunks by `StreamId`, reassembling each stream's
+/// chunks back into whole packets as they complete.
+#[derive(Debug)]
+struct Multiplexer {
+    transport: Simple,
+    next_id: StreamId,
+    queues: HashMap<StreamId, VecDeque<Vec<u8>>>,
+    reassembly: HashMap<StreamId, Vec<u8>>,
+    known_ids: HashSet<StreamId>,
+    pending_accepts: VecDeque<StreamId>,
+    /// If set, `process_data` stops reading from the stream once buffered
+    /// bytes reach this many. Tracked here rather than just passed through
+    /// to `transport`'s own limit, since every frame `transport` decodes is
+    /// immediately drained into `reassembly`/`queues` below: checking only
+    /// `transport.buffered_len()` would see it reset to ~0 on every call no
+    /// matter how much a slow reader had left piled up in those.
+    backpressure_limit: Option<usize>,
+}
+
+impl Multiplexer {
+    fn new(role: Role) -> Self {
+        Multiplexer {
+            transport: Simple::new(),
+            next_id: match role {
+                Role::Initiator => 1,
+                Role::Responder => 2,
+            },
+            queues: HashMap::new(),
+            reassembly: HashMap::new(),
+            known_ids: HashSet::new(),
+            pending_accepts: VecDeque::new(),
+            backpressure_limit: None,
+        }
+    }
+
+    /// Caps how many buffered-but-undelivered bytes `process_data` will
+    /// accumulate before it stops reading from the stream. See
+    /// [`Simple::with_backpressure_limit`].
+    fn with_backpressure_limit(mut self, limit: usize) -> Self {
+        self.backpressure_limit = Some(limit);
+        self
+    }
+
+    /// How many buffered-but-undelivered bytes this multiplexer is holding:
+    /// `transport`'s own unframed input, plus everything already pulled out
+    /// of it into per-stream reassembly buffers and completed-packet queues
+    /// that nobody has read yet.
+    fn buffered_len(&self) -> usize {
+        self.transport.buffered_len()
+            + self.reassembly.values().map(Vec::len).sum::<usize>()
+            + self
+                .queues
+                .values()
+                .flat_map(|queue| queue.iter().map(Vec::len))
+                .sum::<usize>()
+    }
+
+    /// Allocates the next stream id for this side of the connection. Marks
+    /// it as already known so a reply on it isn't mistaken for a
+    /// remote-initiated stream by `apply_bytes` below.
+    fn allocate_id(&mut self) -> StreamId {
+        let id = self.next_id;
+        self.next_id += 2;
+        self.known_ids.insert(id);
+        id
+    }
+
+    /// Feeds already-read `bytes` through the inner transport and routes
+    /// any fully-received frames into their stream's reassembly buffer,
+    /// recording the id of any stream seen for the first time, and queuing
+    /// a packet once its last chunk arrives. Split out from the actual
+    /// socket read (see [`Demuxer::pump`]) so the two can be held under
+    /// different locks.
+    async fn apply_bytes(&mut self, bytes: Bytes, settings: &Settings) -> Result<(), Error> {
+        self.transport.process_bytes(bytes, settings).await?;
+
+        while let Some(raw_frame) = self.transport.receive_raw_packet().await? {
+            let mut cursor = Cursor::new(raw_frame);
+            let stream_id = StreamId::read(&mut cursor, settings)?;
+            let header = ChunkSize::read(&mut cursor, settings)?;
+            let more_follows = header & MORE_FOLLOWS_FLAG != 0;
+            let offset = cursor.position() as usize;
+            let chunk = cursor.into_inner().split_off(offset);
+
+            if self.known_ids.insert(stream_id) {
+                self.pending_accepts.push_back(stream_id);
+            }
+
+            let packet = self.reassembly.entry(stream_id).or_default();
+            packet.extend_from_slice(&chunk);
+
+            if !more_follows {
+                let packet = self.reassembly.remove(&stream_id).unwrap_or_default();
+                self.queues.entry(stream_id).or_default().push_back(packet);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pops the next queued packet for `stream_id`, if one has arrived.
+    fn receive_raw_packet(&mut self, stream_id: StreamId) -> Option<Vec<u8>> {
+        self.queues.get_mut(&stream_id)?.pop_front()
+    }
+
+    /// Pops the id of a remote-initiated stream seen for the first time.
+    fn accept(&mut self) -> Option<StreamId> {
+        self.pending_accepts.pop_front()
+    }
+}
+
+/// Sends a single chunk, prefixed with the stream id it belongs to.
+async fn send_raw_frame<W: AsyncWrite + Send + Unpin>(
+    write: &mut W,
+    stream_id: StreamId,
+    chunk: &[u8],
+    more_follows: bool,
+    settings: &Settings,
+) -> Result<(), Error> {
+    assert!(chunk.len() & !(CHUNK_LENGTH_MASK as usize) == 0);
+
+    let mut framed = Cursor::new(Vec::<u8>::new());
+    stream_id.write(&mut framed, settings)?;
+
+    let header: ChunkSize =
+        (chunk.len() as ChunkSize) | if more_follows { MORE_FOLLOWS_FLAG } else { 0 };
+    header.write(&mut framed, settings)?;
+    framed.write_all(chunk).await?;
+
+    Simple::<FixedSizeCodec>::new()
+        .send_raw_packet(write, &framed.into_inner(), settings)
+        .await
+}
+
+/// The read half of a multiplexed connection, shared by every
+/// [`StreamReceiver`] so that whichever one polls first can demultiplex
+/// frames on behalf of the others.
+///
+/// The socket read and the decode/dispatch step are guarded by separate
+/// locks. Holding one combined lock across both meant a receiver blocked
+/// inside the (potentially long) socket read also blocked every other
+/// receiver from so much as checking whether *its* packet had already
+/// arrived from an earlier read — starving streams that had nothing left
+/// to wait for. Only one `pump` can be in flight at a time (so reads and
+/// the frames they decode stay in order), but `state` is free to be
+/// checked by anyone else the whole time.
+#[derive(Debug)]
+struct Demuxer<S: AsyncRead + Send + Unpin> {
+    pump: Mutex<ReadHalf<S>>,
+    state: Mutex<Multiplexer>,
+}
+
+impl<S: AsyncRead + Send + Unpin> Demuxer<S> {
+    fn new(reader: ReadHalf<S>, multiplexer: Multiplexer) -> Self {
+        Demuxer {
+            pump: Mutex::new(reader),
+            state: Mutex::new(multiplexer),
+        }
+    }
+
+    /// Pops the next queued packet for `stream_id`, if one has already
+    /// arrived.
+    async fn receive_raw_packet(&self, stream_id: StreamId) -> Option<Vec<u8>> {
+        self.state.lock().await.receive_raw_packet(stream_id)
+    }
+
+    /// Pops the id of a remote-initiated stream seen for the first time, if
+    /// one has already arrived.
+    async fn accept(&self) -> Option<StreamId> {
+        self.state.lock().await.accept()
+    }
+
+    async fn allocate_id(&self) -> StreamId {
+        self.state.lock().await.allocate_id()
+    }
+
+    /// Reads one chunk of bytes off the stream and feeds it through the
+    /// demultiplexer, becoming "the" reader for as long as that takes.
+    /// Returns whether the read hit end-of-stream.
+    async fn pump(&self, settings: &Settings) -> Result<bool, Error> {
+        let mut reader = self.pump.lock().await;
+
+        {
+            let state = self.state.lock().await;
+            if let Some(limit) = state.backpressure_limit {
+                if state.buffered_len() >= limit {
+                    return Ok(false);
+                }
+            }
+        }
+
+        match read_chunk(&mut *reader).await? {
+            Some(bytes) => {
+                self.state.lock().await.apply_bytes(bytes, settings).await?;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+/// Schedules outgoing chunks across streams by priority: on every write
+/// opportunity the highest non-empty priority level is served, and within a
+/// level streams are drained round-robin so none of them can monopolize the
+/// socket. Because chunks (not whole packets) are the unit of scheduling, a
+/// high-priority message can preempt an in-flight bulk transfer after at
+/// most one chunk.
+#[derive(Debug)]
+struct Scheduler<S: AsyncWrite + Send + Unpin> {
+    writer: WriteHalf<S>,
+    settings: Settings,
+    /// Streams with at least one chunk ready to send, grouped by priority;
+    /// within a level, the front of the queue goes next, and a served
+    /// stream is requeued at the back.
+    ready: BTreeMap<Priority, VecDeque<StreamId>>,
+    /// Chunks queued per stream, each tagged with whether it is the last
+    /// chunk of its packet.
+    pending: HashMap<StreamId, VecDeque<(Vec<u8>, bool)>>,
+}
+
+impl<S: AsyncWrite + Send + Unpin> Scheduler<S> {
+    fn new(writer: WriteHalf<S>, settings: Settings) -> Self {
+        Scheduler {
+            writer,
+            settings,
+            ready: BTreeMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Splits `packet` into chunks and queues them for `stream_id` at
+    /// `priority`.
+    fn enqueue(&mut self, stream_id: StreamId, priority: Priority, packet: &[u8]) {
+        let was_idle = self.pending.get(&stream_id).map_or(true, VecDeque::is_empty);
+        let queue = self.pending.entry(stream_id).or_default();
+
+        let mut remaining = packet;
+        loop {
+            let len = remaining.len().min(MAX_CHUNK_SIZE);
+            let (chunk, rest) = remaining.split_at(len);
+            let is_last = rest.is_empty();
+            queue.push_back((chunk.to_vec(), is_last));
+
+            if is_last {
+                break;
+            }
+            remaining = rest;
+        }
+
+        if was_idle {
+            self.ready.entry(priority).or_default().push_back(stream_id);
+        }
+    }
+
+    /// Whether `stream_id` still has chunks queued to send.
+    fn is_pending(&self, stream_id: StreamId) -> bool {
+        self.pending.get(&stream_id).map_or(false, |q| !q.is_empty())
+    }
+
+    /// Writes a single chunk from the highest-priority ready stream.
+    /// Returns `false` if nothing was ready to send.
+    async fn write_ready(&mut self) -> Result<bool, Error> {
+        let priority = match self.ready.keys().next_back().copied() {
+            Some(priority) => priority,
+            None => return Ok(false),
+        };
+
+        let level = self.ready.get_mut(&priority).unwrap();
+        let stream_id = level.pop_front().unwrap();
+
+        let queue = self.pending.get_mut(&stream_id).unwrap();
+        let (chunk, is_last) = queue.pop_front().unwrap();
+        let more_follows = !is_last;
+
+        send_raw_frame(
+            &mut self.writer,
+            stream_id,
+            &chunk,
+            more_follows,
+            &self.settings,
+        )
+        .await?;
+
+        if queue.is_empty() {
+            self.pending.remove(&stream_id);
+        } else {
+            // Still has chunks left: rejoin the back of the line so other
+            // streams at this priority get their turn first.
+            level.push_back(stream_id);
+        }
+
+        if level.is_empty() {
+            self.ready.remove(&priority);
+        }
+
+        Ok(true)
+    }
+}
+
+/// The sending half of one logical stream multiplexed over a connection.
+#[derive(Debug)]
+pub struct StreamSender<S: AsyncWrite + Send + Unpin> {
+    id: StreamId,
+    priority: Priority,
+    scheduler: Arc<Mutex<Scheduler<S>>>,
+}
+
+impl<S: AsyncWrite + Send + Unpin> StreamSender<S> {
+    /// The id of the logical stream this sender writes to.
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// The priority this stream is scheduled with.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Sends a packet on this stream, interleaving its chunks with other
+    /// streams' according to priority.
+    pub async fn send_raw_packet(&self, packet: &[u8]) -> Result<(), Error> {
+        {
+            let mut scheduler = self.scheduler.lock().await;
+            scheduler.enqueue(self.id, self.priority, packet);
+        }
+
+        loop {
+            let mut scheduler = self.scheduler.lock().await;
+            scheduler.write_ready().await?;
+            let still_pending = scheduler.is_pending(self.id);
+            drop(scheduler);
+
+            if !still_pending {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The receiving half of one logical stream multiplexed over a connection.
+#[derive(Debug)]
+pub struct StreamReceiver<S: AsyncRead + Send + Unpin> {
+    id: StreamId,
+    demuxer: Arc<Demuxer<S>>,
+    settings: Settings,
+}
+
+impl<S: AsyncRead + Send + Unpin> StreamReceiver<S> {
+    /// The id of the logical stream this receiver reads from.
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Receives the next packet on this stream, reading and demultiplexing
+    /// more frames from the underlying connection as needed.
+    pub async fn receive_raw_packet(&self) -> Result<Option<Vec<u8>>, Error> {
+        let mut eof = false;
+        loop {
+            if let Some(packet) = self.demuxer.receive_raw_packet(self.id).await {
+                return Ok(Some(packet));
+            }
+
+            if eof {
+                return Err(io_error(
+                    "peer closed the connection before completing a packet on this stream",
+                ));
+            }
+
+            eof = self.demuxer.pump(&self.settings).await?;
+        }
+    }
+}
+
+/// A connection that multiplexes many logical streams over one
+/// `AsyncRead + AsyncWrite`, each with its own `StreamId` and send
+/// priority.
+#[derive(Debug)]
+pub struct MultiplexedConnection<S: AsyncRead + AsyncWrite + Send + Unpin> {
+    scheduler: Arc<Mutex<Scheduler<S>>>,
+    demuxer: Arc<Demuxer<S>>,
+    settings: Settings,
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> MultiplexedConnection<S> {
+    /// Creates a new multiplexed connection. `role` decides the parity of
+    /// the stream ids this side allocates via [`open_stream`](Self::open_stream).
+    pub fn new(stream: S, settings: Settings, role: Role) -> Self {
+        Self::with_backpressure_limit(stream, settings, role, None)
+    }
+
+    /// Like [`new`](Self::new), but caps how many buffered-but-undelivered
+    /// bytes the demultiplexer will accumulate before it stops reading from
+    /// the stream. See [`Simple::with_backpressure_limit`].
+    pub fn with_backpressure_limit(
+        stream: S,
+        settings: Settings,
+        role: Role,
+        backpressure_limit: Option<usize>,
+    ) -> Self {
+        let (reader, writer) = split(stream);
+        let mut multiplexer = Multiplexer::new(role);
+
+        if let Some(limit) = backpressure_limit {
+            multiplexer = multiplexer.with_backpressure_limit(limit);
+        }
+
+        MultiplexedConnection {
+            scheduler: Arc::new(Mutex::new(Scheduler::new(writer, settings.clone()))),
+            demuxer: Arc::new(Demuxer::new(reader, multiplexer)),
+            settings,
+        }
+    }
+
+    /// Opens a new, locally-initiated logical stream scheduled at
+    /// `priority`.
+    pub async fn open_stream(&self, priority: Priority) -> (StreamSender<S>, StreamReceiver<S>) {
+        let id = self.demuxer.allocate_id().await;
+        self.stream_handles(id, priority)
+    }
+
+    /// Waits for a remote-initiated stream, returning its handles the first
+    /// time a frame for it arrives. Replies on it are scheduled at
+    /// `priority`.
+    pub async fn accept_stream(
+        &self,
+        priority: Priority,
+    ) -> Result<(StreamSender<S>, StreamReceiver<S>), Error> {
+        let mut eof = false;
+        loop {
+            if let Some(id) = self.demuxer.accept().await {
+                return Ok(self.stream_handles(id, priority));
+            }
+
+            if eof {
+                return Err(io_error(
+                    "peer closed the connection before opening a stream",
+                ));
+            }
+
+            eof = self.demuxer.pump(&self.settings).await?;
+        }
+    }
+
+    fn stream_handles(&self, id: StreamId, priority: Priority) -> (StreamSender<S>, StreamReceiver<S>) {
+        (
+            StreamSender {
+                id,
+                priority,
+                scheduler: self.scheduler.clone(),
+            },
+            StreamReceiver {
+                id,
+                demuxer: self.demuxer.clone(),
+                settings: self.settings.clone(),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use protocol::Settings;
+    use tokio::io::duplex;
+
+    use super::super::inmemory::{InmemoryStream, DEFAULT_CAPACITY};
+    use super::*;
+
+    async fn read_frame_stream_id<R: AsyncRead + Send + Unpin>(
+        transport: &mut Simple<FixedSizeCodec>,
+        reader: &mut R,
+        settings: &Settings,
+    ) -> StreamId {
+        loop {
+            if let Some(raw) = transport.receive_raw_packet().await.unwrap() {
+                return StreamId::read(&mut Cursor::new(raw), settings).unwrap();
+            }
+            transport.process_data(reader, settings).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn higher_priority_streams_are_served_before_lower_ones() {
+        let settings = Settings::default();
+        let (mut client, server) = duplex(16 * 1024);
+        let (_unused, writer) = tokio::io::split(server);
+        let mut scheduler = Scheduler::new(writer, settings.clone());
+
+        // Queue the low-priority stream first so a naive FIFO scheduler
+        // would serve it before the high-priority one enqueued after it.
+        scheduler.enqueue(1, 0, b"low priority payload");
+        scheduler.enqueue(2, 9, b"high priority payload");
+
+        assert!(scheduler.write_ready().await.unwrap());
+        assert!(scheduler.write_ready().await.unwrap());
+
+        // One transport for both reads: both frames may arrive in a single
+        // read, and the second must not be dropped with a throwaway decoder.
+        let mut transport = Simple::<FixedSizeCodec>::new();
+        assert_eq!(read_frame_stream_id(&mut transport, &mut client, &settings).await, 2);
+        assert_eq!(read_frame_stream_id(&mut transport, &mut client, &settings).await, 1);
+    }
+
+    #[tokio::test]
+    async fn reply_on_a_locally_opened_stream_does_not_spuriously_accept() {
+        let settings = Settings::default();
+        let (stream_a, stream_b) = InmemoryStream::pair(DEFAULT_CAPACITY);
+        let a = MultiplexedConnection::new(stream_a, settings.clone(), Role::Initiator);
+        let b = MultiplexedConnection::new(stream_b, settings, Role::Responder);
+
+        let (sender_a, receiver_a) = a.open_stream(DEFAULT_PRIORITY).await;
+        sender_a.send_raw_packet(b"hello").await.unwrap();
+
+        let (sender_b, receiver_b) = b.accept_stream(DEFAULT_PRIORITY).await.unwrap();
+        assert_eq!(
+            receiver_b.receive_raw_packet().await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+
+        sender_b.send_raw_packet(b"world").await.unwrap();
+        assert_eq!(
+            receiver_a.receive_raw_packet().await.unwrap(),
+            Some(b"world".to_vec())
+        );
+
+        // Before the `known_ids` fix, the reply above would have been
+        // mistaken for a new remote-initiated stream and handed out again
+        // here, racing `receiver_a` for the same queue.
+        let second_accept =
+            tokio::time::timeout(Duration::from_millis(200), a.accept_stream(DEFAULT_PRIORITY))
+                .await;
+        assert!(
+            second_accept.is_err(),
+            "accept_stream spuriously fired for a's own locally-opened stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_stream_nobody_reads_is_throttled_by_the_backpressure_limit() {
+        let settings = Settings::default();
+        let (mut writer, mut reader) = duplex(256);
+
+        // Keep sending chunks for one stream without ever finishing it
+        // (`more_follows` always set), as if nobody is reading that
+        // stream. Enough of them that, left unthrottled, this would block
+        // on the duplex's own flow control long before finishing - that's
+        // the point: the multiplexer, not the duplex, must be what stops
+        // this backlog from growing.
+        let writer_settings = settings.clone();
+        let writer_task = tokio::spawn(async move {
+            for _ in 0..1000 {
+                send_raw_frame(&mut writer, 1, &[0u8; 32], true, &writer_settings)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let multiplexer = Multiplexer::new(Role::Responder).with_backpressure_limit(64);
+        let (reader, _unused) = tokio::io::split(reader);
+        let demuxer = Demuxer::new(reader, multiplexer);
+
+        for _ in 0..50 {
+            demuxer.pump(&settings).await.unwrap();
+            let buffered_len = demuxer.state.lock().await.buffered_len();
+            assert!(
+                buffered_len <= 64 * 4,
+                "buffered_len grew well past the backpressure limit: {}",
+                buffered_len,
+            );
+            tokio::task::yield_now().await;
+        }
+
+        // Before this fix, `transport.buffered_len()` reset to ~0 every
+        // call (everything decoded gets moved into `reassembly`
+        // immediately), so the multiplexer never stopped reading and would
+        // eventually drain the writer's entire backlog.
+        let finished = tokio::time::timeout(Duration::from_millis(200), writer_task).await;
+        assert!(
+            finished.is_err(),
+            "writer should still be blocked: the multiplexer should have stopped reading \
+             once buffered_len reached the backpressure limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_receiver_with_an_already_queued_packet_is_not_starved_by_another_ones_pending_read() {
+        let settings = Settings::default();
+        let (mut writer, reader) = duplex(16 * 1024);
+        let (reader, _unused) = tokio::io::split(reader);
+        let demuxer = Arc::new(Demuxer::new(reader, Multiplexer::new(Role::Responder)));
+
+        // Stream 2 gets a chunk with `more_follows` set and nothing after
+        // it, so it never completes: any `pump` that goes looking for more
+        // of it blocks on the socket read forever, becoming "the" reader
+        // for as long as that takes.
+        send_raw_frame(&mut writer, 2, b"incomplete", true, &settings)
+            .await
+            .unwrap();
+        // Stream 1's packet arrives right behind it, so both are sitting in
+        // the duplex together for one `pump` to decode in a single read.
+        send_raw_frame(&mut writer, 1, b"already here", false, &settings)
+            .await
+            .unwrap();
+
+        // Pump once so stream 1's packet lands in `queues` and stream 2's
+        // partial chunk lands in `reassembly`, then hand out stream 2's
+        // receiver and start a read that has nothing left to do but block
+        // on the next (never-arriving) socket read.
+        demuxer.pump(&settings).await.unwrap();
+        let stream_2 = StreamReceiver {
+            id: 2,
+            demuxer: demuxer.clone(),
+            settings: settings.clone(),
+        };
+        let blocked_read = tokio::spawn(async move { stream_2.receive_raw_packet().await });
+
+        tokio::task::yield_now().await;
+
+        // Stream 1's packet was already queued before `blocked_read` took
+        // the pump lock, so reading it must not wait behind that in-flight
+        // socket read. Before the fix, both shared one lock across the
+        // whole call and this would hang for the full timeout instead.
+        let stream_1 = StreamReceiver {
+            id: 1,
+            demuxer: demuxer.clone(),
+            settings,
+        };
+        let result = tokio::time::timeout(Duration::from_millis(200), stream_1.receive_raw_packet())
+            .await;
+        assert_eq!(
+            result
+                .expect("stream 1's receiver was starved by stream 2's in-flight pump")
+                .unwrap(),
+            Some(b"already here".to_vec())
+        );
+
+        blocked_read.abort();
+    }
+}
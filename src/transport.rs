@@ -3,19 +3,31 @@ use std::io::Cursor;
 use std::mem;
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use protocol::{Error, Parcel, Settings};
 
+use super::bytes_buf::BytesBuf;
+
+fn io_error(message: impl Into<String>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into()).into()
+}
+
 #[async_trait]
 pub trait Transport {
+    /// Reads whatever bytes are currently available from `read` and feeds
+    /// them through the transport's decoder. Returns `Ok(true)` once the
+    /// underlying read signals end-of-stream (a zero-length read), so that
+    /// callers that loop on this waiting for a packet/chunk can stop
+    /// retrying and report the disconnect instead of spinning forever.
     async fn process_data<R: AsyncRead + Send + Unpin>(
         &mut self,
         read: &mut R,
         settings: &Settings,
-    ) -> Result<(), Error>;
+    ) -> Result<bool, Error>;
 
-    async fn receive_raw_packet(&mut self) -> Result<Option<Vec<u8>>, Error>;
+    async fn receive_raw_packet(&mut self) -> Result<Option<Bytes>, Error>;
 
     async fn send_raw_packet<W: AsyncWrite + Send + Unpin>(
         &mut self,
@@ -23,99 +35,385 @@ pub trait Transport {
         packet: &[u8],
         settings: &Settings,
     ) -> Result<(), Error>;
+
+    /// Switches the transport into streaming-receive mode, so that
+    /// subsequently processed bytes are decoded as chunks rather than a
+    /// single whole packet.
+    fn begin_receiving_stream(&mut self);
+
+    /// Pops the next decoded item of a streamed body, if one is ready.
+    async fn receive_raw_chunk(&mut self) -> Result<Option<RawChunk>, Error>;
+
+    /// Sends a single chunk of a streamed body. `more_follows` must be `true`
+    /// for every chunk except the last one.
+    async fn send_raw_chunk<W: AsyncWrite + Send + Unpin>(
+        &mut self,
+        write: &mut W,
+        chunk: &[u8],
+        more_follows: bool,
+        settings: &Settings,
+    ) -> Result<(), Error>;
 }
 
 /// The type that we use to describe packet sizes.
 pub type PacketSize = u32;
 
-/// The current state.
+/// The type that we use to describe a streamed chunk header.
+pub type ChunkSize = u16;
+
+/// The largest payload a single streamed chunk may carry.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Set on a chunk header when more chunks follow; clear on the last chunk.
+pub(crate) const MORE_FOLLOWS_FLAG: ChunkSize = 0x8000;
+/// The bits of a chunk header that encode its payload length.
+pub(crate) const CHUNK_LENGTH_MASK: ChunkSize = 0x7fff;
+
+/// A single decoded item of a streamed body.
 #[derive(Clone, Debug)]
-enum State {
-    /// We are awaiting packet size bytes.
-    AwaitingSize(Vec<u8>),
-    AwaitingPacket {
-        size: PacketSize,
-        received_data: Vec<u8>,
-    },
+pub enum RawChunk {
+    /// A chunk of payload data.
+    Data(Bytes),
+    /// The end of the stream was reached.
+    End,
 }
 
-/// A simple transport.
-#[derive(Clone, Debug)]
-pub struct Simple {
-    state: State,
-    packets: VecDeque<Vec<u8>>,
+impl RawChunk {
+    fn len(&self) -> usize {
+        match self {
+            RawChunk::Data(data) => data.len(),
+            RawChunk::End => 0,
+        }
+    }
 }
 
-impl Simple {
-    pub fn new() -> Self {
-        Simple {
-            state: State::AwaitingSize(Vec::new()),
-            packets: VecDeque::new(),
+/// A pluggable wire framing: turns a payload into bytes to send and carves
+/// payloads back out of whatever has been received so far.
+///
+/// Only whole-packet framing goes through a `Codec` — the fixed `u16`
+/// chunk header used by the streaming API is part of the wire format
+/// itself and isn't swappable.
+pub trait Codec: Clone + std::fmt::Debug + Send {
+    /// Appends the framed encoding of `payload` onto `out`.
+    fn encode_frame(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+        settings: &Settings,
+    ) -> Result<(), Error>;
+
+    /// Attempts to carve one frame off the front of `buf`, removing its
+    /// bytes and returning the decoded payload. Returns `Ok(None)` if `buf`
+    /// doesn't yet hold a whole frame.
+    fn decode_frame(
+        &mut self,
+        buf: &mut BytesBuf,
+        settings: &Settings,
+    ) -> Result<Option<Bytes>, Error>;
+}
+
+/// The original framing: a fixed-width `PacketSize` length prefix followed
+/// by that many payload bytes.
+#[derive(Clone, Debug, Default)]
+pub struct FixedSizeCodec;
+
+impl Codec for FixedSizeCodec {
+    fn encode_frame(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+        settings: &Settings,
+    ) -> Result<(), Error> {
+        let start = out.len() as u64;
+        let mut cursor = Cursor::new(&mut *out);
+        cursor.set_position(start);
+        (payload.len() as PacketSize).write(&mut cursor, settings)?;
+        out.extend_from_slice(payload);
+
+        Ok(())
+    }
+
+    fn decode_frame(
+        &mut self,
+        buf: &mut BytesBuf,
+        settings: &Settings,
+    ) -> Result<Option<Bytes>, Error> {
+        let header_len = mem::size_of::<PacketSize>();
+        let view = buf.as_contiguous();
+
+        if view.len() < header_len {
+            return Ok(None);
         }
+
+        let size = PacketSize::read(&mut Cursor::new(&view[..header_len]), settings)? as usize;
+
+        if view.len() < header_len + size {
+            return Ok(None);
+        }
+
+        buf.take(header_len);
+        Ok(Some(buf.take(size)))
     }
+}
 
-    async fn process_bytes(&mut self, bytes: &[u8], settings: &Settings) -> Result<(), Error> {
-        let mut read = Cursor::new(bytes);
+/// Frames payloads with a LEB128 varint length prefix, so small messages
+/// cost a single length byte instead of a whole `u32`.
+#[derive(Clone, Debug, Default)]
+pub struct VarintCodec;
+
+impl Codec for VarintCodec {
+    fn encode_frame(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+        _settings: &Settings,
+    ) -> Result<(), Error> {
+        let mut len = payload.len() as u64;
 
         loop {
-            match self.state.clone() {
-                State::AwaitingSize(mut size_bytes) => {
-                    let remaining_bytes = mem::size_of::<PacketSize>() - size_bytes.len();
+            let mut byte = (len & 0x7f) as u8;
+            len >>= 7;
 
-                    let mut received_bytes = vec![0; remaining_bytes];
-                    let bytes_read = std::io::Read::read(&mut read, &mut received_bytes)?;
-                    received_bytes.drain(bytes_read..);
+            if len != 0 {
+                byte |= 0x80;
+            }
 
-                    assert_eq!(received_bytes.len(), bytes_read);
+            out.push(byte);
 
-                    size_bytes.extend(received_bytes.into_iter());
+            if len == 0 {
+                break;
+            }
+        }
 
-                    if size_bytes.len() == mem::size_of::<PacketSize>() {
-                        let mut size_buffer = Cursor::new(size_bytes);
+        out.extend_from_slice(payload);
 
-                        let size = PacketSize::read(&mut size_buffer, settings).unwrap();
+        Ok(())
+    }
 
-                        // We are now ready to receive packet data.
-                        self.state = State::AwaitingPacket {
-                            size,
-                            received_data: Vec::new(),
-                        }
-                    } else {
-                        // Still waiting to receive the whole packet.
-                        self.state = State::AwaitingSize(size_bytes);
-                        break;
-                    }
+    fn decode_frame(
+        &mut self,
+        buf: &mut BytesBuf,
+        _settings: &Settings,
+    ) -> Result<Option<Bytes>, Error> {
+        let view = buf.as_contiguous();
+        let mut len: u64 = 0;
+
+        for (i, byte) in view.iter().enumerate() {
+            // A u64 needs at most 10 bytes (7 bits each); a
+            // peer sending more than that (or a truncated/corrupt stream of
+            // 0x80 bytes) is malformed, not just "not a full frame yet" -
+            // shifting any further would panic with an overflowing shift.
+            if i >= 10 {
+                return Err(io_error("varint length prefix is too long"));
+            }
+
+            len |= ((byte & 0x7f) as u64) << (i * 7);
+
+            if byte & 0x80 == 0 {
+                let header_len = i + 1;
+                let size = len as usize;
+
+                if view.len() < header_len + size {
+                    return Ok(None);
                 }
-                State::AwaitingPacket {
-                    size,
-                    mut received_data,
-                } => {
-                    let remaining_bytes = (size as usize) - received_data.len();
-                    assert!(remaining_bytes > 0);
 
-                    let mut received_bytes = vec![0; remaining_bytes];
-                    let bytes_read = read.read(&mut received_bytes).await?;
-                    received_bytes.drain(bytes_read..);
+                buf.take(header_len);
+                return Ok(Some(buf.take(size)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Frames payloads as a run of bytes terminated by a delimiter, for
+/// line-oriented text protocols. The payload itself must not contain the
+/// delimiter.
+#[derive(Clone, Debug)]
+pub struct DelimiterCodec {
+    delimiter: u8,
+}
 
-                    assert_eq!(received_bytes.len(), bytes_read);
+impl DelimiterCodec {
+    pub fn new(delimiter: u8) -> Self {
+        DelimiterCodec { delimiter }
+    }
+}
 
-                    received_data.extend(received_bytes.into_iter());
+impl Default for DelimiterCodec {
+    /// Delimits frames with `\n`, as in most line-oriented protocols.
+    fn default() -> Self {
+        DelimiterCodec::new(b'\n')
+    }
+}
 
-                    assert!(received_data.len() <= (size as usize));
+impl Codec for DelimiterCodec {
+    fn encode_frame(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+        _settings: &Settings,
+    ) -> Result<(), Error> {
+        out.extend_from_slice(payload);
+        out.push(self.delimiter);
 
-                    if (size as usize) == received_data.len() {
-                        self.packets.push_back(received_data);
+        Ok(())
+    }
 
-                        // Start reading the next packet.
-                        self.state = State::AwaitingSize(Vec::new());
+    fn decode_frame(
+        &mut self,
+        buf: &mut BytesBuf,
+        _settings: &Settings,
+    ) -> Result<Option<Bytes>, Error> {
+        let view = buf.as_contiguous();
+
+        match view.iter().position(|&byte| byte == self.delimiter) {
+            Some(pos) => {
+                let payload = buf.take(pos);
+                buf.take(1); // discard the delimiter itself
+
+                Ok(Some(payload))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The current state. Any bytes already received for the current state
+/// live in `Simple::input`, not here, so this stays cheap to copy.
+#[derive(Clone, Copy, Debug)]
+enum State {
+    /// We are decoding ordinary packets via the configured `Codec`.
+    Idle,
+    /// We are awaiting a streamed chunk's header bytes.
+    AwaitingChunkHeader,
+    /// We are awaiting a streamed chunk's payload bytes.
+    AwaitingChunk { more_follows: bool, remaining: usize },
+}
+
+/// A simple transport, parameterized over the `Codec` used to frame
+/// ordinary packets.
+#[derive(Clone, Debug)]
+pub struct Simple<C: Codec = FixedSizeCodec> {
+    codec: C,
+    /// Bytes received but not yet decoded.
+    input: BytesBuf,
+    state: State,
+    packets: VecDeque<Bytes>,
+    chunks: VecDeque<RawChunk>,
+    /// If set, `process_data` stops reading from the stream once buffered
+    /// bytes reach this many, applying backpressure to the peer until the
+    /// consumer drains `packets`/`chunks`.
+    backpressure_limit: Option<usize>,
+}
+
+impl<C: Codec + Default> Simple<C> {
+    pub fn new() -> Self {
+        Self::with_codec(C::default())
+    }
+}
+
+impl<C: Codec + Default> Default for Simple<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Codec> Simple<C> {
+    /// Creates a new transport using `codec` to frame ordinary packets.
+    pub fn with_codec(codec: C) -> Self {
+        Simple {
+            codec,
+            input: BytesBuf::new(),
+            state: State::Idle,
+            packets: VecDeque::new(),
+            chunks: VecDeque::new(),
+            backpressure_limit: None,
+        }
+    }
+
+    /// Caps how many buffered-but-undelivered bytes (input not yet framed,
+    /// plus framed packets/chunks not yet consumed) `process_data` will
+    /// accumulate before it stops reading from the stream.
+    pub fn with_backpressure_limit(mut self, limit: usize) -> Self {
+        self.backpressure_limit = Some(limit);
+        self
+    }
+
+    /// How many buffered-but-undelivered bytes (input not yet framed, plus
+    /// framed packets/chunks not yet consumed) this transport is holding.
+    /// Exposed so wrappers like the multiplexer, which pull frames out of
+    /// here into their own per-stream buffers, can fold this into their own
+    /// backpressure accounting instead of checking only what's left behind.
+    pub fn buffered_len(&self) -> usize {
+        self.input.len()
+            + self.packets.iter().map(Bytes::len).sum::<usize>()
+            + self.chunks.iter().map(RawChunk::len).sum::<usize>()
+    }
+
+    /// Feeds already-read `bytes` through the codec/chunk state machine,
+    /// queuing whatever that completes into `packets`/`chunks`. Exposed
+    /// (separately from [`Transport::process_data`], which also does the
+    /// actual socket read) so a caller that needs the read and the decode
+    /// under different locks can do them as two independent steps.
+    pub async fn process_bytes(&mut self, bytes: Bytes, settings: &Settings) -> Result<(), Error> {
+        self.input.extend(bytes);
+
+        loop {
+            match self.state {
+                State::Idle => match self.codec.decode_frame(&mut self.input, settings)? {
+                    Some(payload) => self.packets.push_back(payload),
+                    None => break,
+                },
+                State::AwaitingChunkHeader => {
+                    let header_len = mem::size_of::<ChunkSize>();
+
+                    if self.input.len() < header_len {
+                        break;
+                    }
+
+                    let header_bytes = self.input.take(header_len);
+                    let header =
+                        ChunkSize::read(&mut Cursor::new(&header_bytes[..]), settings).unwrap();
+
+                    let more_follows = header & MORE_FOLLOWS_FLAG != 0;
+                    let length = (header & CHUNK_LENGTH_MASK) as usize;
+
+                    if length == 0 {
+                        // A zero-length chunk with the flag clear is the
+                        // end-of-stream marker; one with the flag set is
+                        // just an empty chunk, so keep reading.
+                        if more_follows {
+                            self.state = State::AwaitingChunkHeader;
+                        } else {
+                            self.chunks.push_back(RawChunk::End);
+                            self.state = State::Idle;
+                        }
                     } else {
-                        // Keep reading the current packet.
-                        self.state = State::AwaitingPacket {
-                            size,
-                            received_data,
+                        self.state = State::AwaitingChunk {
+                            more_follows,
+                            remaining: length,
                         };
+                    }
+                }
+                State::AwaitingChunk {
+                    more_follows,
+                    remaining,
+                } => {
+                    if self.input.len() < remaining {
                         break;
                     }
+
+                    self.chunks
+                        .push_back(RawChunk::Data(self.input.take(remaining)));
+
+                    if more_follows {
+                        self.state = State::AwaitingChunkHeader;
+                    } else {
+                        // The last chunk also ends the stream.
+                        self.chunks.push_back(RawChunk::End);
+                        self.state = State::Idle;
+                    }
                 }
             }
         }
@@ -126,32 +424,55 @@ impl Simple {
 
 const BUFFER_SIZE: usize = 10000;
 
+/// Reads one chunk of bytes directly off `read`, without decoding it.
+/// Returns `None` on end-of-stream (a zero-length read). Split out of
+/// `Simple::process_data`'s read step so a caller that wants to hold a
+/// different (or no) lock across the decode than across the actual socket
+/// read — see the multiplexer's `Demuxer` — can do the two as separate
+/// operations instead of one lock covering both.
+pub async fn read_chunk<R: AsyncRead + Send + Unpin>(
+    read: &mut R,
+) -> Result<Option<Bytes>, Error> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let bytes_read = read.read(&mut buffer).await?;
+
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+
+    buffer.truncate(bytes_read);
+    Ok(Some(Bytes::from(buffer)))
+}
+
 #[async_trait]
-impl Transport for Simple {
+impl<C: Codec> Transport for Simple<C> {
     async fn process_data<R: AsyncRead + Send + Unpin>(
         &mut self,
         read: &mut R,
         settings: &Settings,
-    ) -> Result<(), Error> {
-        // Load the data into a temporary buffer before we process it.
+    ) -> Result<bool, Error> {
         loop {
-            let mut buffer = [0u8; BUFFER_SIZE];
-            let bytes_read = read.read(&mut buffer).await.unwrap();
-            let buffer = &buffer[0..bytes_read];
+            if let Some(limit) = self.backpressure_limit {
+                if self.buffered_len() >= limit {
+                    return Ok(false);
+                }
+            }
+
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            let bytes_read = read.read(&mut buffer).await?;
 
             if bytes_read == 0 {
-                break;
-            } else {
-                self.process_bytes(buffer, settings).await?;
+                return Ok(true);
+            }
 
-                // We didn't fill the whole buffer so stop now.
-                if bytes_read != BUFFER_SIZE {
-                    break;
-                }
+            buffer.truncate(bytes_read);
+            self.process_bytes(Bytes::from(buffer), settings).await?;
+
+            // We didn't fill the whole buffer so stop now.
+            if bytes_read != BUFFER_SIZE {
+                return Ok(false);
             }
         }
-
-        Ok(())
     }
 
     async fn send_raw_packet<W: AsyncWrite + Send + Unpin>(
@@ -160,18 +481,65 @@ impl Transport for Simple {
         packet: &[u8],
         settings: &Settings,
     ) -> Result<(), Error> {
-        let mut w = Cursor::new(Vec::<u8>::new());
-        // Prefix the packet size.
-        (packet.len() as PacketSize).write(&mut w, settings)?;
-        // Write the packet data.
-        w.write_all(&packet).await?;
+        let mut framed = Vec::new();
+        self.codec.encode_frame(packet, &mut framed, settings)?;
 
-        write.write(&w.into_inner()).await?;
+        write.write_all(&framed).await?;
 
         Ok(())
     }
 
-    async fn receive_raw_packet(&mut self) -> Result<Option<Vec<u8>>, Error> {
+    async fn receive_raw_packet(&mut self) -> Result<Option<Bytes>, Error> {
         Ok(self.packets.pop_front())
     }
+
+    fn begin_receiving_stream(&mut self) {
+        self.state = State::AwaitingChunkHeader;
+    }
+
+    async fn receive_raw_chunk(&mut self) -> Result<Option<RawChunk>, Error> {
+        Ok(self.chunks.pop_front())
+    }
+
+    async fn send_raw_chunk<W: AsyncWrite + Send + Unpin>(
+        &mut self,
+        write: &mut W,
+        chunk: &[u8],
+        more_follows: bool,
+        settings: &Settings,
+    ) -> Result<(), Error> {
+        assert!(chunk.len() <= MAX_CHUNK_SIZE);
+
+        let mut framed = Cursor::new(Vec::<u8>::new());
+
+        let header: ChunkSize = (chunk.len() as ChunkSize)
+            | if more_follows { MORE_FOLLOWS_FLAG } else { 0 };
+        header.write(&mut framed, settings)?;
+        std::io::Write::write_all(&mut framed, chunk)?;
+
+        write.write_all(&framed.into_inner()).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::Settings;
+
+    use super::*;
+
+    #[test]
+    fn varint_decode_frame_errors_instead_of_panicking_on_an_unterminated_prefix() {
+        let mut codec = VarintCodec;
+        let settings = Settings::default();
+
+        // 11 continuation bytes, none of which ever terminate the prefix:
+        // more than the 10 a u64 can hold. Before the fix, the 11th shift
+        // (`<< 70`) would panic instead of being reported as a decode error.
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(&[0x80; 11]));
+
+        assert!(codec.decode_frame(&mut buf, &settings).is_err());
+    }
 }